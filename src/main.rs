@@ -24,15 +24,15 @@
 use memmap2::Mmap;
 use std::{
     cmp::min,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env::args,
     fmt,
     fmt::{Display, Formatter},
     fs::File,
-    // io::{Read, Seek, SeekFrom},
-    str::FromStr,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Seek, SeekFrom},
     sync::{
-        mpsc::{channel, Receiver, Sender},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
         Arc, Mutex,
     },
     thread,
@@ -40,21 +40,94 @@ use std::{
 
 const CHUNK_SIZE: usize = 100_000_000;
 
+/// Depth of the bounded chunk queue between the reader and the workers; kept
+/// small so the reader runs a little ahead without buffering the whole file.
+const QUEUE_DEPTH: usize = 4;
+
+/// Odd multiplier from the FxHash family; mixes entropy across all bits.
+const FX_SEED: u64 = 0x517cc1b727220a95;
+
+/// Multiply-xor hasher for short station-name keys — far cheaper than the
+/// default SipHash for the ~10k distinct lookups in the inner loop.
+#[derive(Default)]
+struct FxHasher {
+    hash: u64,
+}
+
+impl Hasher for FxHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for c in &mut chunks {
+            let word = u64::from_le_bytes(c.try_into().unwrap());
+            self.hash = (self.hash ^ word).wrapping_mul(FX_SEED);
+        }
+        let mut word = 0u64;
+        for (i, &b) in chunks.remainder().iter().enumerate() {
+            word |= (b as u64) << (i * 8);
+        }
+        self.hash = (self.hash ^ word).wrapping_mul(FX_SEED);
+    }
+}
+
+#[derive(Clone, Default)]
+struct BuildFxHasher;
+
+impl BuildHasher for BuildFxHasher {
+    type Hasher = FxHasher;
+
+    fn build_hasher(&self) -> FxHasher {
+        FxHasher::default()
+    }
+}
+
+/// Per-thread station map, keyed by the raw name bytes and hashed with
+/// [`FxHasher`]. Keys are owned so the worker is agnostic to whether its bytes
+/// came from an mmap slice or an owned read buffer.
+type Partial = HashMap<Box<[u8]>, CityInfo, BuildFxHasher>;
+
+/// A newline-aligned slice of the input, supplied by either I/O backend: the
+/// mmap backend borrows directly from the mapping, the seek backend hands over
+/// a freshly-read buffer.
+enum Chunk<'f> {
+    Mapped(&'f [u8]),
+    Owned(Vec<u8>),
+}
+
+impl Chunk<'_> {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Chunk::Mapped(s) => s,
+            Chunk::Owned(v) => v,
+        }
+    }
+}
+
 struct Aggregate {
-    result: HashMap<String, CityInfo>,
+    result: BTreeMap<String, CityInfo>,
 }
 
 #[derive(Clone, Debug)]
 struct CityInfo {
-    min: f64,
-    mean: f64,
-    max: f64,
-    count: usize,
+    min: i64,
+    max: i64,
+    sum: i64,
+    count: u64,
 }
 
-struct Measurement {
-    name: String,
-    temp: f64,
+/// Round to one decimal place, half-up away from zero, matching the 1BRC
+/// reference output (nudge by 0.05 toward the value's sign, then truncate).
+fn round1(v: f64) -> f64 {
+    let nudged = if v < 0.0 { v - 0.05 } else { v + 0.05 };
+    (nudged * 10.0).trunc() / 10.0
+}
+
+struct Measurement<'a> {
+    name: &'a [u8],
+    temp: i32,
 }
 
 struct SplitReader<'f> {
@@ -62,49 +135,160 @@ struct SplitReader<'f> {
     cursor: usize,
 }
 
-#[derive(Debug)]
-struct MeasurementParseError;
+/// A byte range `[start, stop)` over the input file, snapped to newline
+/// boundaries. Used by the seek backend when mmap is unavailable.
+struct FileChunk {
+    path: String,
+    start: u64,
+    stop: u64,
+}
+
+impl FileChunk {
+    /// Open a fresh, independently-seeked handle and read exactly this range
+    /// into an owned buffer, so workers need not share a file cursor.
+    fn read(&self) -> io::Result<Vec<u8>> {
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(self.start))?;
+        let mut buf = vec![0u8; (self.stop - self.start) as usize];
+        f.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Precompute newline-aligned byte ranges over `path`: split at every
+/// `CHUNK_SIZE` boundary, then scan forward to the following `\n` so no line
+/// straddles two chunks.
+fn split_file(path: &str) -> io::Result<Vec<FileChunk>> {
+    let mut f = File::open(path)?;
+    let len = f.metadata()?.len();
+    let mut chunks = Vec::new();
+    let mut start = 0u64;
+    while start < len {
+        let mut stop = min(start as usize + CHUNK_SIZE, len as usize) as u64;
+        if stop < len {
+            f.seek(SeekFrom::Start(stop))?;
+            let mut byte = [0u8; 1];
+            loop {
+                if f.read(&mut byte)? == 0 {
+                    stop = len;
+                    break;
+                }
+                stop += 1;
+                if byte[0] == b'\n' {
+                    break;
+                }
+            }
+        }
+        chunks.push(FileChunk {
+            path: path.to_string(),
+            start,
+            stop,
+        });
+        start = stop;
+    }
+    Ok(chunks)
+}
+
 
 impl Display for Aggregate {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        let printable = self.result.iter().map(|(key, value)| format!("{}={}\n", key, value))
+        let printable = self
+            .result
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
             .collect::<Vec<_>>()
-            .join("\n");
-        write!(f, "{}", printable)
+            .join(", ");
+        write!(f, "{{{}}}", printable)
     }
 }
 
 impl Display for CityInfo {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{:.1}/{:.1}/{:.1}", self.min, self.mean, self.max)
+        let mean = self.sum as f64 / self.count as f64 / 10.0;
+        write!(
+            f,
+            "{:.1}/{:.1}/{:.1}",
+            round1(self.min as f64 / 10.0),
+            round1(mean),
+            round1(self.max as f64 / 10.0)
+        )
     }
 }
 
-impl FromStr for Measurement {
-    type Err = MeasurementParseError;
+impl CityInfo {
+    /// Render this station's stats as a JSON object, including `count` so the
+    /// shard is losslessly mergeable downstream.
+    fn to_json(&self) -> String {
+        let mean = self.sum as f64 / self.count as f64 / 10.0;
+        format!(
+            "{{\"min\":{:.1},\"mean\":{},\"max\":{:.1},\"count\":{}}}",
+            round1(self.min as f64 / 10.0),
+            mean,
+            round1(self.max as f64 / 10.0),
+            self.count
+        )
+    }
+}
 
-    fn from_str(line: &str) -> Result<Self, Self::Err> {
-        let mut l = line.split(';');
-        let name = l.next().expect("bad line: no delimiter").to_string();
-        let temp = l
-            .next()
-            .expect("bad line: no value")
-            .parse::<f64>()
-            .expect("bad line: invalid value");
-        Ok(Self { name, temp })
+/// Print one worker's partial map as a single-line JSON object, escaping `"`
+/// and `\` in station names. One line per thread keeps shards independently
+/// parseable.
+fn emit_json(results: &Partial) {
+    let mut out = String::from("{");
+    for (i, (k, v)) in results.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        out.push('"');
+        for c in String::from_utf8_lossy(k).chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                _ => out.push(c),
+            }
+        }
+        out.push_str("\":");
+        out.push_str(&v.to_json());
+    }
+    out.push('}');
+    println!("{}", out);
+}
+
+impl<'a> Measurement<'a> {
+    /// Parse a single `name;-?\d{1,2}\.\d` line straight from the chunk bytes,
+    /// borrowing the name and folding the temperature into an `i32` count of
+    /// tenths of a degree. No allocation, no float parsing.
+    fn from_bytes(line: &'a [u8]) -> Self {
+        let sep = line
+            .iter()
+            .position(|&b| b == b';')
+            .expect("bad line: no delimiter");
+        let name = &line[..sep];
+        let mut neg = false;
+        let mut acc: i32 = 0;
+        for &b in &line[sep + 1..] {
+            match b {
+                b'-' => neg = true,
+                b'.' => {}
+                _ => acc = acc * 10 + (b - b'0') as i32,
+            }
+        }
+        let temp = if neg { -acc } else { acc };
+        Self { name, temp }
     }
 }
 
 impl Aggregate {
-    fn new(partials: Vec<HashMap<String, CityInfo>>) -> Self {
-        let mut result = HashMap::<String, CityInfo>::new();
+    fn new(partials: Vec<Partial>) -> Self {
+        let mut result = BTreeMap::<String, CityInfo>::new();
         for p in partials {
             for (k, v) in p {
+                let k = String::from_utf8_lossy(&k).into_owned();
                 if let Some(curr) = result.get_mut(&k) {
-                    curr.min = f64::min(curr.min, v.min);
-                    curr.count += 1;
-                    curr.mean = (curr.mean + v.mean) / (curr.count + v.count) as f64;
-                    curr.max = f64::max(curr.max, v.max);
+                    curr.min = curr.min.min(v.min);
+                    curr.max = curr.max.max(v.max);
+                    curr.sum += v.sum;
+                    curr.count += v.count;
                 } else {
                     result.insert(k, v);
                 }
@@ -119,7 +303,7 @@ impl<'f> SplitReader<'f> {
         Self { f, cursor: 0 }
     }
 
-    fn next(&mut self) -> Option<Arc<[u8]>> {
+    fn next(&mut self) -> Option<&'f [u8]> {
         let l = self.f.len() - 1;
         let start = self.cursor;
         if start >= l {
@@ -130,7 +314,7 @@ impl<'f> SplitReader<'f> {
                 end += 1;
             }
             self.cursor = end + 1;
-            Some(self.f[start..end].into())
+            Some(&self.f[start..end])
         }
     }
 
@@ -153,64 +337,137 @@ impl<'f> SplitReader<'f> {
     // }
 }
 
-fn process(r: Arc<Mutex<SplitReader>>, t: Sender<HashMap<String, CityInfo>>, _i: usize) {
-    let mut results = HashMap::<String, CityInfo>::new();
+fn process(r: Arc<Mutex<Receiver<Chunk>>>, t: Sender<Partial>, _i: usize, json: bool) {
+    let mut results = Partial::default();
     loop {
-        let buf;
-        {
-            let mut reader = r.lock().expect("lost reader");
-            buf = reader.next();
-        }
-        if let Some(buf) = buf {
-            let buf = unsafe { std::str::from_utf8_unchecked(&buf) };
-            for line in buf.lines() {
-                let new = Measurement::from_str(line).expect("bad line");
-                if let Some(curr) = results.get_mut(&new.name) {
-                    curr.min = f64::min(curr.min, new.temp);
+        let chunk = {
+            let reader = r.lock().expect("lost reader");
+            reader.recv()
+        };
+        if let Ok(chunk) = chunk {
+            for line in chunk.as_bytes().split(|&b| b == b'\n') {
+                if line.is_empty() {
+                    continue;
+                }
+                let new = Measurement::from_bytes(line);
+                let temp = new.temp as i64;
+                if let Some(curr) = results.get_mut(new.name) {
+                    curr.min = curr.min.min(temp);
+                    curr.max = curr.max.max(temp);
+                    curr.sum += temp;
                     curr.count += 1;
-                    curr.mean = curr.mean + (new.temp - curr.mean) / curr.count as f64;
-                    curr.max = f64::max(curr.max, new.temp);
                 } else {
                     results.insert(
-                        new.name.to_owned(),
+                        new.name.into(),
                         CityInfo {
-                            min: new.temp,
-                            mean: new.temp,
-                            max: new.temp,
+                            min: temp,
+                            max: temp,
+                            sum: temp,
                             count: 1,
                         },
                     );
                 }
             }
         } else {
-            t.send(results).expect("lost writer");
+            // In JSON mode each worker prints its own shard and sends nothing
+            // back; otherwise the map is handed to the central reducer.
+            if json {
+                emit_json(&results);
+            } else {
+                t.send(results).expect("lost writer");
+            }
             break;
         }
     }
 }
 
-fn main() {
-    let fname = args().nth(1).unwrap_or("data/measurements.txt".to_string());
-    let file = File::open(fname).expect("file not found");
-    let map = unsafe { Mmap::map(&file) }.expect("error opening file");
-    let reader = Arc::new(Mutex::new(SplitReader::new(&map)));
-    let (tx, rx): (
-        Sender<HashMap<String, CityInfo>>,
-        Receiver<HashMap<String, CityInfo>>,
-    ) = channel();
-    let cores = thread::available_parallelism().unwrap().into();
+/// Spawn the worker pool behind a single producer (`producer`), drain their
+/// partial maps, and fold them together. The producer is whichever I/O backend
+/// was selected; everything downstream is identical. In `json` mode each worker
+/// emits its own shard directly and `None` is returned — there is nothing left
+/// to reduce centrally.
+fn run<'f, F>(cores: usize, json: bool, producer: F) -> Option<Aggregate>
+where
+    F: FnOnce(SyncSender<Chunk<'f>>) + Send + 'f,
+{
+    let (tx, rx): (Sender<Partial>, Receiver<Partial>) = channel();
 
     thread::scope(|s| {
+        // A single producer feeds newline-aligned chunks onto the bounded queue;
+        // workers drain it without contending over chunk discovery.
+        let (chunk_tx, chunk_rx) = sync_channel::<Chunk<'f>>(QUEUE_DEPTH);
+        let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+        s.spawn(move || producer(chunk_tx));
         for i in 0..cores {
-            let r = reader.clone();
+            let r = chunk_rx.clone();
             let t = tx.clone();
-            s.spawn(move || process(r, t, i));
+            s.spawn(move || process(r, t, i, json));
         }
     });
 
+    if json {
+        return None;
+    }
+
     let results = (0..cores)
         .map(|_| rx.recv().expect("received mal data"))
         .collect::<Vec<_>>();
-    let agg = Aggregate::new(results);
-    println!("{}", agg);
+    Some(Aggregate::new(results))
+}
+
+fn main() {
+    let mut fname = None;
+    let mut force_file = false;
+    let mut json = false;
+    for a in args().skip(1) {
+        if a == "--file" {
+            force_file = true;
+        } else if a == "--json" {
+            json = true;
+        } else {
+            fname = Some(a);
+        }
+    }
+    let fname = fname.unwrap_or_else(|| "data/measurements.txt".to_string());
+    let file = File::open(&fname).expect("file not found");
+    let cores = thread::available_parallelism().unwrap().into();
+
+    // Prefer mmap, but fall back to the seek backend when it is forced off or
+    // the mapping fails (some network mounts and sandboxes reject mmap).
+    let map = if force_file {
+        None
+    } else {
+        unsafe { Mmap::map(&file) }.ok()
+    };
+
+    let agg = match map {
+        Some(map) => run(cores, json, |tx| {
+            let mut reader = SplitReader::new(&map);
+            while let Some(chunk) = reader.next() {
+                if tx.send(Chunk::Mapped(chunk)).is_err() {
+                    break;
+                }
+            }
+        }),
+        None => {
+            let chunks = split_file(&fname).expect("error scanning file");
+            run(cores, json, move |tx| {
+                for c in chunks {
+                    match c.read() {
+                        Ok(buf) => {
+                            if tx.send(Chunk::Owned(buf)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+            })
+        }
+    };
+    // JSON mode prints per-thread shards itself; only the reducer path has a
+    // single aggregate to print here.
+    if let Some(agg) = agg {
+        println!("{}", agg);
+    }
 }